@@ -0,0 +1,140 @@
+//! A constant-folding and peephole pass over `Expr`/`Stmt` trees, run by
+//! `FunctionCtx::compile` on each statement before it is compiled, so
+//! constant subexpressions shrink to a single `Lit` (and the arithmetic
+//! `Instr`s for them disappear entirely) rather than being compiled as-is.
+
+use super::{Binop, Expr, Name, Stmt, Unop};
+use bytecode::Val;
+
+/// Evaluates `op` applied to a literal operand, or `None` if folding
+/// doesn't apply (wrong `Val` variant for the op).
+fn eval_unop(op: Unop, val: &Val) -> Option<Val> {
+    match (op, val) {
+        (Unop::Negate, &Val::Int(i)) => Some(Val::Int(i.wrapping_neg())),
+        (Unop::Not, &Val::Bool(b)) => Some(Val::Bool(!b)),
+        _ => None,
+    }
+}
+
+/// Evaluates `op` applied to two literal operands, or `None` if folding
+/// doesn't apply. Division/remainder by zero deliberately return `None`
+/// rather than `Val`s built from a compile-time panic, leaving them as
+/// runtime ops so `vm::RuntimeError::DivByZero` still fires as expected.
+fn eval_binop(op: Binop, left: &Val, right: &Val) -> Option<Val> {
+    use self::Binop::*;
+    match (op, left, right) {
+        (Add, &Val::Int(a), &Val::Int(b)) => Some(Val::Int(a.wrapping_add(b))),
+        (Sub, &Val::Int(a), &Val::Int(b)) => Some(Val::Int(a.wrapping_sub(b))),
+        (Mul, &Val::Int(a), &Val::Int(b)) => Some(Val::Int(a.wrapping_mul(b))),
+        (Div, &Val::Int(a), &Val::Int(b)) if b != 0 => Some(Val::Int(a.wrapping_div(b))),
+        (Rem, &Val::Int(a), &Val::Int(b)) if b != 0 => Some(Val::Int(a.wrapping_rem(b))),
+        (And, &Val::Bool(a), &Val::Bool(b)) => Some(Val::Bool(a && b)),
+        (Orr, &Val::Bool(a), &Val::Bool(b)) => Some(Val::Bool(a || b)),
+        (Xor, &Val::Bool(a), &Val::Bool(b)) => Some(Val::Bool(a ^ b)),
+        (Gt, &Val::Int(a), &Val::Int(b)) => Some(Val::Bool(a > b)),
+        (Lt, &Val::Int(a), &Val::Int(b)) => Some(Val::Bool(a < b)),
+        (Geq, &Val::Int(a), &Val::Int(b)) => Some(Val::Bool(a >= b)),
+        (Leq, &Val::Int(a), &Val::Int(b)) => Some(Val::Bool(a <= b)),
+        (Eq, a, b) => Some(Val::Bool(a == b)),
+        (Neq, a, b) => Some(Val::Bool(a != b)),
+        _ => None,
+    }
+}
+
+/// Folds constant subexpressions of `expr` into a single `Lit`, and applies
+/// a few algebraic simplifications (`x*1`, `x+0`, `!!x`) along the way.
+pub fn fold(expr: Expr<Name>) -> Expr<Name> {
+    use self::Expr::*;
+    match expr {
+        Lit(val) => Lit(val),
+        Var(name) => Var(name),
+        Unop(op, arg) => match (op, fold(*arg)) {
+            // !!x == x
+            (super::Unop::Not, Unop(super::Unop::Not, inner)) => *inner,
+            (op, Lit(val)) => match eval_unop(op, &val) {
+                Some(result) => Lit(result),
+                None => Unop(op, Box::new(Lit(val))),
+            },
+            (op, arg) => Unop(op, Box::new(arg)),
+        },
+        Binop(op, left, right) => match (op, fold(*left), fold(*right)) {
+            (op, Lit(l), Lit(r)) => match eval_binop(op, &l, &r) {
+                Some(result) => Lit(result),
+                None => Binop(op, Box::new(Lit(l)), Box::new(Lit(r))),
+            },
+            (super::Binop::Mul, left, Lit(Val::Int(1))) | (super::Binop::Mul, Lit(Val::Int(1)), left) => left,
+            (super::Binop::Add, left, Lit(Val::Int(0))) | (super::Binop::Add, Lit(Val::Int(0)), left) => left,
+            (op, left, right) => Binop(op, Box::new(left), Box::new(right)),
+        },
+        Call(func, args) => Call(Box::new(fold(*func)), args.into_iter().map(fold).collect()),
+        Index(tup, idx) => Index(Box::new(fold(*tup)), Box::new(fold(*idx))),
+        Mktup(parts) => Mktup(parts.into_iter().map(fold).collect()),
+    }
+}
+
+/// Walks a whole statement tree, folding every `Expr` it contains.
+pub fn fold_stmt(stmt: Stmt<Name>) -> Stmt<Name> {
+    use self::Stmt::*;
+    match stmt {
+        Declare(name) => Declare(name),
+        RawExpr(expr) => RawExpr(fold(expr)),
+        Assign(name, expr) => Assign(name, fold(expr)),
+        If(cond, true_block, false_block) => If(fold(cond), fold_block(true_block), fold_block(false_block)),
+        While(cond, block) => While(fold(cond), fold_block(block)),
+        Continue => Continue,
+        Break => Break,
+        Return(expr) => Return(fold(expr)),
+        Defn(name, params, body) => Defn(name, params, fold_block(body)),
+    }
+}
+
+pub fn fold_block(block: Vec<Stmt<Name>>) -> Vec<Stmt<Name>> {
+    block.into_iter().map(fold_stmt).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(i: i64) -> Expr<Name> {
+        Expr::Lit(Val::Int(i))
+    }
+
+    #[test]
+    fn folds_constant_arithmetic() {
+        let expr = Expr::Binop(Binop::Add, Box::new(int(2)), Box::new(int(3)));
+        match fold(expr) {
+            Expr::Lit(Val::Int(5)) => {}
+            other => panic!("expected Lit(Int(5)), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn simplifies_mul_by_one() {
+        let x = Expr::Var(Name { id: 0 });
+        let expr = Expr::Binop(Binop::Mul, Box::new(x), Box::new(int(1)));
+        match fold(expr) {
+            Expr::Var(n) => assert_eq!(n, Name { id: 0 }),
+            other => panic!("expected Var(0), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn simplifies_double_negation() {
+        let x = Expr::Var(Name { id: 0 });
+        let expr = Expr::Unop(Unop::Not, Box::new(Expr::Unop(Unop::Not, Box::new(x))));
+        match fold(expr) {
+            Expr::Var(n) => assert_eq!(n, Name { id: 0 }),
+            other => panic!("expected Var(0), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn leaves_division_by_zero_unfolded() {
+        let expr = Expr::Binop(Binop::Div, Box::new(int(1)), Box::new(int(0)));
+        match fold(expr) {
+            Expr::Binop(Binop::Div, _, _) => {}
+            other => panic!("expected an unfolded Div, got {:?}", other),
+        }
+    }
+}
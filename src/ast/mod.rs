@@ -1,10 +1,18 @@
 use std::collections::HashMap;
+use std::mem;
 use bytecode::{Val, Instr, Addr};
 
 #[cfg(test)]
 mod tests;
+pub mod fold;
+pub mod vm;
 
-#[derive(Debug)]
+/// Largest number of `Mktup` elements compiled into one live batch of temp
+/// registers before it's flushed into the tuple and freed; see the `Mktup`
+/// arm of `compile_expr_ctx`.
+const MKTUP_BATCH: usize = 50;
+
+#[derive(Debug, Clone)]
 pub enum Expr<N> {
     Lit(Val),
     Var(N),
@@ -15,7 +23,7 @@ pub enum Expr<N> {
     Mktup(Vec<Expr<N>>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Stmt<N> {
     Declare(N),
     RawExpr(Expr<N>),
@@ -25,7 +33,11 @@ pub enum Stmt<N> {
     Continue,
     Break,
     Return(Expr<N>),
-    Defn(Vec<N>, Vec<Stmt<N>>),
+    /// Defines a function and binds the resulting closure to `N`, the same
+    /// way `Assign` binds an expression's result — `N` must already be
+    /// `Declare`d (or be an enclosing/upvalue name) so the closure has
+    /// somewhere to live.
+    Defn(N, Vec<N>, Vec<Stmt<N>>),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -57,12 +69,110 @@ pub struct Name {
     id: usize,
 }
 
+/// Where a compiled expression's result should end up: an optional target
+/// register, plus a flag for when the result isn't used at all. Threading
+/// this through `compile_expr` lets the common "compile into this local"
+/// and "compile for side effects only" cases avoid redundant `Copy`s and
+/// temp registers instead of always materializing into a fresh temp.
+#[derive(Debug, Clone, Copy)]
+struct ExprContext {
+    target: Option<Addr>,
+    discard: bool,
+    // Whether `target` is safe to compile directly into even though it
+    // isn't a declared local (see `reg` vs `forced_reg`).
+    forced: bool,
+}
+
+impl ExprContext {
+    /// No preference; fall back to allocating a fresh temporary.
+    fn any() -> ExprContext {
+        ExprContext { target: None, discard: false, forced: false }
+    }
+
+    /// Compile into this specific register if at all possible. Only
+    /// declared locals are honored directly; a non-local target (e.g. some
+    /// other temp) still falls back to a fresh temporary, since `savereg`
+    /// can't otherwise tell whether that register is free for the
+    /// expression to clobber. Use `forced_reg` when the caller guarantees
+    /// the target temp is reserved for exactly this purpose.
+    fn reg(target: Addr) -> ExprContext {
+        ExprContext { target: Some(target), discard: false, forced: false }
+    }
+
+    /// Compile into this specific register even if it isn't a declared
+    /// local, because the caller owns it outright for this purpose (e.g.
+    /// `Call` reserves `base`/`arg_reg` via `push_tmp` before compiling into
+    /// them, so there's no risk of the expression clobbering a live value).
+    fn forced_reg(target: Addr) -> ExprContext {
+        ExprContext { target: Some(target), discard: false, forced: true }
+    }
+
+    /// The result is never used; expressions with no side effects can
+    /// skip materializing it into a register entirely.
+    fn discard() -> ExprContext {
+        ExprContext { target: None, discard: true, forced: false }
+    }
+}
+
+/// Tracks the not-yet-resolved `break`/`continue` jumps belonging to a
+/// single loop. Each entry is the index (within whichever instruction
+/// vector currently owns it) of a placeholder `Jump(0)` that needs its
+/// offset patched in once the loop finishes compiling.
+#[derive(Debug, PartialEq)]
+struct LoopCtx {
+    break_jumps: Vec<usize>,
+    continue_jumps: Vec<usize>,
+}
+
+/// Where a captured upvalue's value comes from, as seen from the function
+/// that captures it: either a register that's a local in the immediately
+/// enclosing function, or an upvalue the enclosing function itself already
+/// captured (chaining the capture through however many scopes separate the
+/// definition from the use).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpvalSource {
+    ParentLocal(Addr),
+    ParentUpval(Addr),
+}
+
+/// A fully-compiled function body. Closures over it are built at runtime by
+/// a `Closure` instruction that indexes into the defining function's
+/// `protos` list, reading `upvalues` to know where each capture comes from.
+#[derive(Debug, PartialEq)]
+pub struct Proto {
+    pub code: Vec<Instr>,
+    pub consts: Vec<Val>,
+    pub arity: usize,
+    pub max_reg: Addr,
+    pub upvalues: Vec<UpvalSource>,
+    pub protos: Vec<Proto>,
+}
+
+/// Where a `Var` lookup resolved to.
+enum VarLoc {
+    Local(Addr),
+    Upvalue(Addr),
+    Global,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct FunctionCtx {
     vars: HashMap<Name, Addr>,
     consts: Vec<Val>,
     free_reg: Addr,
     max_reg: Addr,
+    // Stack of enclosing loops, innermost last, so `break`/`continue`
+    // always resolve against `loops.last()`.
+    loops: Vec<LoopCtx>,
+    // The function this one is nested inside, if any. Swapped in and out
+    // of `self` (see `Defn`) rather than held as a borrow, since a
+    // `FunctionCtx` needs `&mut self` access to both its own and its
+    // ancestors' state while resolving upvalues.
+    parent: Option<Box<FunctionCtx>>,
+    upvalues: Vec<UpvalSource>,
+    upvalue_names: HashMap<Name, Addr>,
+    // Prototypes of functions `Defn`ed directly inside this one.
+    protos: Vec<Proto>,
 }
 
 impl FunctionCtx {
@@ -72,9 +182,62 @@ impl FunctionCtx {
             consts: Vec::new(),
             free_reg: 0,
             max_reg: 0,
+            loops: Vec::new(),
+            parent: None,
+            upvalues: Vec::new(),
+            upvalue_names: HashMap::new(),
+            protos: Vec::new(),
+        }
+    }
+
+    /// Resolves a name to a local register, an upvalue, or (if it's bound
+    /// in no enclosing function either) the global scope. Looking a name
+    /// up in an enclosing function and finding it registers an upvalue in
+    /// every function from here down to wherever it was actually found, so
+    /// a deeply nested closure chains through one upvalue per scope.
+    fn resolve_var(&mut self, name: &Name) -> VarLoc {
+        if let Some(&reg) = self.vars.get(name) {
+            return VarLoc::Local(reg);
+        }
+        if let Some(&idx) = self.upvalue_names.get(name) {
+            return VarLoc::Upvalue(idx);
+        }
+
+        let found_in_parent = match self.parent {
+            Some(ref mut parent) => parent.resolve_var(name),
+            None => VarLoc::Global,
+        };
+
+        match found_in_parent {
+            VarLoc::Local(reg) => VarLoc::Upvalue(self.add_upvalue(*name, UpvalSource::ParentLocal(reg))),
+            VarLoc::Upvalue(idx) => VarLoc::Upvalue(self.add_upvalue(*name, UpvalSource::ParentUpval(idx))),
+            VarLoc::Global => VarLoc::Global,
         }
     }
 
+    fn add_upvalue(&mut self, name: Name, source: UpvalSource) -> Addr {
+        let idx = self.upvalues.len() as Addr;
+        self.upvalues.push(source);
+        self.upvalue_names.insert(name, idx);
+        idx
+    }
+
+    /// The number of registers a `vm::Vm` needs to allocate to run this
+    /// function's compiled code.
+    pub fn max_reg(&self) -> Addr {
+        self.max_reg
+    }
+
+    /// The constant pool `Const` instructions index into.
+    pub fn consts(&self) -> &[Val] {
+        &self.consts
+    }
+
+    /// The prototypes this function's `Closure` instructions index into.
+    pub fn protos(&self) -> &[Proto] {
+        &self.protos
+    }
+
     fn push_tmp(&mut self) -> Addr {
         let reg = self.free_reg;
         self.free_reg += 1;
@@ -93,6 +256,34 @@ impl FunctionCtx {
         self.free_reg = addr;
     }
 
+    /// Counts of break/continue placeholders already recorded against the
+    /// innermost loop, taken before compiling some sub-block. Used with
+    /// `splice_loop_aware` to tell which entries were just added by that
+    /// sub-block (and so still need shifting) from ones already resolved.
+    fn loop_jump_counts(&self) -> (usize, usize) {
+        match self.loops.last() {
+            Some(ctx) => (ctx.break_jumps.len(), ctx.continue_jumps.len()),
+            None => (0, 0),
+        }
+    }
+
+    /// Appends `src` onto `dst`, first shifting any break/continue
+    /// placeholder indices recorded in the innermost loop while `src` was
+    /// being compiled (i.e. those past `before`) by `dst.len()`, since those
+    /// indices were recorded relative to `src` and are about to move.
+    fn splice_loop_aware(&mut self, dst: &mut Vec<Instr>, mut src: Vec<Instr>, before: (usize, usize)) {
+        let offset = dst.len();
+        if let Some(ctx) = self.loops.last_mut() {
+            for idx in ctx.break_jumps.iter_mut().skip(before.0) {
+                *idx += offset;
+            }
+            for idx in ctx.continue_jumps.iter_mut().skip(before.1) {
+                *idx += offset;
+            }
+        }
+        dst.append(&mut src);
+    }
+
     fn get_const(&mut self, val: &Val) -> Addr {
         for (i, k) in self.consts.iter().enumerate() {
             if k == val {
@@ -103,20 +294,65 @@ impl FunctionCtx {
         (self.consts.len() - 1) as u8
     }
 
+    /// Resolves an `ExprContext` to the register an expression should
+    /// compile into: the requested target if it's a real local slot
+    /// (reusing the same "is it a temp" heuristic as `pop_tmp`) or the
+    /// caller has otherwise guaranteed it's safe to target directly
+    /// (`ExprContext::forced_reg`), otherwise a fresh temp.
+    fn savereg(&mut self, ctx: ExprContext) -> Addr {
+        match ctx.target {
+            Some(reg) if ctx.forced || (reg as usize) < self.vars.len() => reg,
+            _ => self.push_tmp(),
+        }
+    }
+
     /// Returns a tuple containing the register with the result of the expr
     /// and a Vect of Instrs that generate the expression
     pub fn compile_expr(&mut self, expr: &Expr<Name>) -> (Addr, Vec<Instr>) {
+        self.compile_expr_ctx(expr, ExprContext::any())
+    }
+
+    /// Like `compile_expr`, but `ctx` lets the caller request that the
+    /// result land in a specific register (skipping an explicit `Copy`
+    /// when the expression can freely choose its own destination) or
+    /// declare that the result is unused (skipping the compile entirely
+    /// for a `Lit` or `Var`; other expressions still compile into a temp).
+    fn compile_expr_ctx(&mut self, expr: &Expr<Name>, ctx: ExprContext) -> (Addr, Vec<Instr>) {
         use self::Expr::*;
         match *expr {
             Lit(ref val) => {
-                let reg = self.push_tmp();
+                if ctx.discard {
+                    return (0, vec![]);
+                }
+                let reg = self.savereg(ctx);
                 let instr = Instr::Const(reg, self.get_const(val));
                 (reg, vec![instr])
             }
-            Var(ref name) => (self.vars[name], vec![]),
+            Var(ref name) => match self.resolve_var(name) {
+                VarLoc::Local(reg) => {
+                    if ctx.discard {
+                        return (reg, vec![]);
+                    }
+                    // A local's value already lives in its own register, so
+                    // it can only be made to appear elsewhere via an
+                    // explicit Copy.
+                    match ctx.target {
+                        Some(dest) if dest != reg => (dest, vec![Instr::Copy(dest, reg)]),
+                        _ => (reg, vec![]),
+                    }
+                }
+                VarLoc::Upvalue(idx) => {
+                    if ctx.discard {
+                        return (0, vec![]);
+                    }
+                    let reg = self.savereg(ctx);
+                    (reg, vec![Instr::GetUpval(reg, idx)])
+                }
+                VarLoc::Global => panic!("unbound variable: globals aren't supported yet"),
+            },
             Unop(ref op, ref arg) => unimplemented!(),
             Binop(op, ref left, ref right) => {
-                let reg = self.push_tmp();
+                let reg = self.savereg(ctx);
                 let (left_dest, mut left_code) = self.compile_expr(left);
                 let (right_dest, mut right_code) = self.compile_expr(right);
                 use self::Binop::*;
@@ -144,37 +380,115 @@ impl FunctionCtx {
                 left_code.push(instr);
                 (reg, left_code)
             }
-            Call(ref func, ref args) => unimplemented!(),
+            Call(ref func, ref args) => {
+                // The callee and its arguments must end up contiguous, in
+                // order, starting at a fresh `base` register, so each is
+                // compiled directly into its slot rather than wherever it
+                // would otherwise land.
+                let base = self.push_tmp();
+                // `base` is reserved via `push_tmp` just above purely to
+                // hold the callee, so it's safe to compile directly into
+                // even though it isn't a declared local — `forced_reg`
+                // tells `savereg` that, avoiding a redundant `Copy` for
+                // anything that doesn't unconditionally own its result
+                // register (e.g. `Lit`, `Binop`, `Mktup`, an upvalue `Var`). `Var`
+                // locals still `Copy` into `base`, since their value
+                // already lives in another register that has to be read,
+                // not computed.
+                let (func_reg, mut code) = self.compile_expr_ctx(func, ExprContext::forced_reg(base));
+                if func_reg != base {
+                    code.push(Instr::Copy(base, func_reg));
+                    self.pop_tmp(func_reg);
+                }
+
+                let mut arg_regs = Vec::with_capacity(args.len());
+                for arg in args {
+                    let arg_reg = self.push_tmp();
+                    let (reg, mut arg_code) = self.compile_expr_ctx(arg, ExprContext::forced_reg(arg_reg));
+                    code.append(&mut arg_code);
+                    if reg != arg_reg {
+                        code.push(Instr::Copy(arg_reg, reg));
+                        self.pop_tmp(reg);
+                    }
+                    arg_regs.push(arg_reg);
+                }
+
+                code.push(Instr::Call(base, args.len() as u8));
+
+                // The call's result takes over `base`; free the argument
+                // registers above it (highest first, same as Mktup).
+                for arg_reg in arg_regs.into_iter().rev() {
+                    self.pop_tmp(arg_reg);
+                }
+
+                // Only actually move the result when `ctx` asks for a real
+                // local or an explicitly forced temp: `savereg` would
+                // otherwise push a *new* temp above `base` to satisfy an
+                // un-forced non-local target, which would then sit above
+                // `base` and break the "free the highest temp first" stack
+                // discipline `pop_tmp` relies on. `any`/`discard` contexts
+                // are left in `base` for the caller to deal with, exactly
+                // like `Var`'s own upvalue/local temps are left wherever
+                // they land.
+                let reg = match ctx.target {
+                    Some(dest) if ctx.forced || (dest as usize) < self.vars.len() => {
+                        code.push(Instr::Copy(dest, base));
+                        self.pop_tmp(base);
+                        dest
+                    }
+                    _ => base,
+                };
+                (reg, code)
+            }
             Index(ref tup, ref idx) => unimplemented!(),
             Mktup(ref parts) => {
-                let reg = self.push_tmp();
+                assert!(!parts.is_empty());
+                let reg = self.savereg(ctx);
                 let mut code = vec![];
-                let mut part_addrs = vec![];
-                let mut start_addr = None;
-                // @TODO: any way to make start_addr not mutable?
-                for (i, part) in parts.iter().enumerate() {
-                    let (part_dest, mut part_code) = self.compile_expr(part);
-                    code.append(&mut part_code);
-                    part_addrs.push(part_dest);
-
-                    if i == 0 {
-                        start_addr = Some(part_dest);
+
+                // Wide tuple literals are flushed in fixed-size batches
+                // rather than keeping every element's temp register live
+                // until one final MkTup, so peak register usage stays
+                // bounded by MKTUP_BATCH instead of growing with arity.
+                // A tuple with `parts.len() <= MKTUP_BATCH` is a single
+                // batch and so still compiles to the one MkTup it always
+                // did.
+                let mut appended = false;
+                for batch in parts.chunks(MKTUP_BATCH) {
+                    let mut part_addrs = vec![];
+                    let mut start_addr = None;
+                    // @TODO: any way to make start_addr not mutable?
+                    for (i, part) in batch.iter().enumerate() {
+                        let (part_dest, mut part_code) = self.compile_expr(part);
+                        code.append(&mut part_code);
+                        part_addrs.push(part_dest);
+
+                        if i == 0 {
+                            start_addr = Some(part_dest);
+                        }
                     }
-                }
 
-                assert!(start_addr.is_some());
-                let start_addr = start_addr.unwrap();
-                // Minus one required because MkTup is inclusive at the ends,
-                // If we want to make a tuple out of registers 2, 3, 4, then
-                // that means we have the starting addr of 2, and 3 parts.
-                // Thus, the end addr is 2 + 3 - 1 = 4
-                let end_addr = (start_addr + parts.len() as u8 - 1) as u8;
-
-                // Must do this backwards due to the highest registers being popped first
-                for addr in part_addrs.iter().rev() {
-                    self.pop_tmp(*addr);
+                    assert!(start_addr.is_some());
+                    let start_addr = start_addr.unwrap();
+                    // Minus one required because MkTup/AppendTup are
+                    // inclusive at the ends. If we want to make a tuple out
+                    // of registers 2, 3, 4, then that means we have the
+                    // starting addr of 2, and 3 parts. Thus, the end addr
+                    // is 2 + 3 - 1 = 4
+                    let end_addr = (start_addr + batch.len() as u8 - 1) as u8;
+
+                    // Must do this backwards due to the highest registers being popped first
+                    for addr in part_addrs.iter().rev() {
+                        self.pop_tmp(*addr);
+                    }
+
+                    code.push(if appended {
+                        Instr::AppendTup(reg, start_addr, end_addr)
+                    } else {
+                        Instr::MkTup(reg, start_addr, end_addr)
+                    });
+                    appended = true;
                 }
-                code.push(Instr::MkTup(reg, start_addr, end_addr));
 
                 (reg, code)
             },
@@ -191,19 +505,36 @@ impl FunctionCtx {
                 vec![]
             }
             RawExpr(ref expr) => {
-                let (reg, code) = self.compile_expr(expr);
-                self.pop_tmp(reg);
+                let (reg, code) = self.compile_expr_ctx(expr, ExprContext::discard());
+                // A discard-compiled `Lit`/`Var` emits no code and never
+                // allocates a temp, so there's nothing to free in that case.
+                if !code.is_empty() {
+                    self.pop_tmp(reg);
+                }
                 code
             }
-            Assign(ref name, ref expr) => {
-                let dest = self.vars[name];
-                let (reg, mut code) = self.compile_expr(expr);
-                code.push(Instr::Copy(dest, reg));
+            Assign(ref name, ref expr) => match self.resolve_var(name) {
+                VarLoc::Local(dest) => {
+                    let (reg, mut code) = self.compile_expr_ctx(expr, ExprContext::reg(dest));
 
-                self.pop_tmp(reg);
+                    // The expression may already have compiled its result
+                    // straight into `dest` (any expr that can freely choose
+                    // its destination does); only copy if it couldn't.
+                    if reg != dest {
+                        code.push(Instr::Copy(dest, reg));
+                        self.pop_tmp(reg);
+                    }
 
-                code
-            }
+                    code
+                }
+                VarLoc::Upvalue(idx) => {
+                    let (reg, mut code) = self.compile_expr(expr);
+                    code.push(Instr::SetUpval(idx, reg));
+                    self.pop_tmp(reg);
+                    code
+                }
+                VarLoc::Global => panic!("unbound variable: globals aren't supported yet"),
+            },
             If(ref cond, ref true_block, ref false_block) => {
                 use bytecode::Instr::*;
 
@@ -212,27 +543,135 @@ impl FunctionCtx {
                 code.push(CondJump(cond_dest, 2, 1));
                 self.pop_tmp(cond_dest);
 
-                let mut true_code = self.compile(true_block);
-                let mut false_code = self.compile(false_block);
+                let before_true = self.loop_jump_counts();
+                let true_code = self.compile(true_block);
 
                 code.push(Jump(true_code.len() as i16 + 2));
-                code.append(&mut true_code);
+                self.splice_loop_aware(&mut code, true_code, before_true);
+                // Compiled only after the true block is spliced, so the
+                // splice above shifts none of the false block's placeholders.
+                let before_false = self.loop_jump_counts();
+                let false_code = self.compile(false_block);
                 code.push(Jump(false_code.len() as i16 + 1));
-                code.append(&mut false_code);
+                self.splice_loop_aware(&mut code, false_code, before_false);
                 code
             },
-            While(ref cond, ref block) => unimplemented!(),
-            Continue => unimplemented!(),
-            Break => unimplemented!(),
-            Return(ref expr) => unimplemented!(),
-            Defn(ref params, ref body) => unimplemented!(),
+            While(ref cond, ref block) => {
+                use bytecode::Instr::*;
+
+                self.loops.push(LoopCtx {
+                    break_jumps: Vec::new(),
+                    continue_jumps: Vec::new(),
+                });
+
+                // The condition is re-evaluated on every iteration, so its
+                // first instruction (index 0 of this block) also doubles as
+                // `continue`'s target.
+                let (cond_dest, mut code) = self.compile_expr(cond);
+                self.pop_tmp(cond_dest);
+                let exit_idx = code.len();
+                // False-offset is a placeholder, patched below once we know
+                // where the loop ends; true falls through to the body.
+                code.push(CondJump(cond_dest, 1, 0));
+
+                let before_body = self.loop_jump_counts();
+                let body_code = self.compile(block);
+                self.splice_loop_aware(&mut code, body_code, before_body);
+
+                let back_idx = code.len();
+                code.push(Jump(-(back_idx as i16)));
+
+                let end = code.len();
+                code[exit_idx] = CondJump(cond_dest, 1, (end - exit_idx) as i16);
+
+                let ctx = self.loops.pop().unwrap();
+                for idx in ctx.break_jumps {
+                    code[idx] = Jump((end - idx) as i16);
+                }
+                for idx in ctx.continue_jumps {
+                    code[idx] = Jump(-(idx as i16));
+                }
+
+                code
+            },
+            Continue => {
+                use bytecode::Instr::Jump;
+
+                let ctx = self.loops.last_mut().expect("`continue` used outside of a loop");
+                ctx.continue_jumps.push(0);
+                vec![Jump(0)]
+            },
+            Break => {
+                use bytecode::Instr::Jump;
+
+                let ctx = self.loops.last_mut().expect("`break` used outside of a loop");
+                ctx.break_jumps.push(0);
+                vec![Jump(0)]
+            },
+            Return(ref expr) => {
+                let (reg, mut code) = self.compile_expr(expr);
+                code.push(Instr::Return(reg));
+                self.pop_tmp(reg);
+                code
+            },
+            Defn(ref name, ref params, ref body) => {
+                // Compile the function body against a fresh child context,
+                // temporarily becoming that context ourselves (see the
+                // `parent` field) so nested `Var` lookups can walk back out
+                // to `self` as the enclosing scope.
+                let outer = mem::replace(self, FunctionCtx::new());
+                self.parent = Some(Box::new(outer));
+
+                for param in params {
+                    let reg = self.push_tmp();
+                    self.vars.insert(param.clone(), reg);
+                }
+
+                let body_code = self.compile(body);
+
+                let FunctionCtx { consts, max_reg, upvalues, protos, parent, .. } =
+                    mem::replace(self, FunctionCtx::new());
+                *self = *parent.expect("Defn always sets a parent before compiling its body");
+
+                let proto = Proto {
+                    code: body_code,
+                    consts,
+                    arity: params.len(),
+                    max_reg,
+                    upvalues,
+                    protos,
+                };
+                let proto_idx = self.protos.len() as u8;
+                self.protos.push(proto);
+
+                // Bind the closure into `name`'s slot the same way `Assign`
+                // binds an expression's result, rather than leaving it in an
+                // anonymous temp no later statement could ever reach.
+                match self.resolve_var(name) {
+                    VarLoc::Local(dest) => vec![Instr::Closure(dest, proto_idx)],
+                    VarLoc::Upvalue(idx) => {
+                        let tmp = self.push_tmp();
+                        let code = vec![Instr::Closure(tmp, proto_idx), Instr::SetUpval(idx, tmp)];
+                        self.pop_tmp(tmp);
+                        code
+                    }
+                    VarLoc::Global => panic!("unbound variable: globals aren't supported yet"),
+                }
+            },
         }
     }
 
     pub fn compile(&mut self, code: &[Stmt<Name>]) -> Vec<Instr> {
         let mut result = Vec::new();
         for stmt in code {
-            result.append(&mut self.compile_stmt(stmt));
+            // Constant-fold/peephole this statement before compiling it, so
+            // e.g. `2 + 3` never makes it into the instruction stream as an
+            // `Add`. `If`/`While`/`Defn` recurse back into `compile`, so
+            // nested blocks get folded too.
+            let stmt = fold::fold_stmt(stmt.clone());
+            let before = self.loop_jump_counts();
+            let stmt_code = self.compile_stmt(&stmt);
+            self.splice_loop_aware(&mut result, stmt_code, before);
         }
         result
     }
@@ -0,0 +1,262 @@
+use super::*;
+use super::vm::Vm;
+use bytecode::{Instr, Val};
+
+fn name(id: usize) -> Name {
+    Name { id }
+}
+
+fn run(stmts: &[Stmt<Name>]) -> Val {
+    let mut ctx = FunctionCtx::new();
+    let code = ctx.compile(stmts);
+    Vm::new(ctx.max_reg(), ctx.consts(), ctx.protos()).run(&code).unwrap()
+}
+
+#[test]
+fn assign_and_return_arithmetic() {
+    let x = name(0);
+    let stmts = vec![
+        Stmt::Declare(x),
+        Stmt::Assign(x, Expr::Binop(Binop::Add, Box::new(Expr::Lit(Val::Int(2))), Box::new(Expr::Lit(Val::Int(3))))),
+        Stmt::Return(Expr::Var(x)),
+    ];
+    assert_eq!(run(&stmts), Val::Int(5));
+}
+
+#[test]
+fn if_picks_the_taken_branch() {
+    let stmts = vec![
+        Stmt::If(
+            Expr::Lit(Val::Bool(false)),
+            vec![Stmt::Return(Expr::Lit(Val::Int(1)))],
+            vec![Stmt::Return(Expr::Lit(Val::Int(2)))],
+        ),
+    ];
+    assert_eq!(run(&stmts), Val::Int(2));
+}
+
+#[test]
+fn while_loop_sums_with_break_and_continue() {
+    let i = name(0);
+    let sum = name(1);
+    let stmts = vec![
+        Stmt::Declare(i),
+        Stmt::Assign(i, Expr::Lit(Val::Int(0))),
+        Stmt::Declare(sum),
+        Stmt::Assign(sum, Expr::Lit(Val::Int(0))),
+        Stmt::While(
+            Expr::Lit(Val::Bool(true)),
+            vec![
+                Stmt::Assign(i, Expr::Binop(Binop::Add, Box::new(Expr::Var(i)), Box::new(Expr::Lit(Val::Int(1))))),
+                Stmt::If(
+                    Expr::Binop(Binop::Gt, Box::new(Expr::Var(i)), Box::new(Expr::Lit(Val::Int(5)))),
+                    vec![Stmt::Break],
+                    vec![],
+                ),
+                Stmt::If(
+                    Expr::Binop(Binop::Eq, Box::new(Expr::Var(i)), Box::new(Expr::Lit(Val::Int(3)))),
+                    vec![Stmt::Continue],
+                    vec![],
+                ),
+                Stmt::Assign(sum, Expr::Binop(Binop::Add, Box::new(Expr::Var(sum)), Box::new(Expr::Var(i)))),
+            ],
+        ),
+        Stmt::Return(Expr::Var(sum)),
+    ];
+    // 1 + 2 + 4 + 5, skipping 3 via `continue` and stopping after 5 via `break`.
+    assert_eq!(run(&stmts), Val::Int(12));
+}
+
+#[test]
+fn mktup_gathers_parts_in_order() {
+    let stmts = vec![
+        Stmt::Return(Expr::Mktup(vec![
+            Expr::Lit(Val::Int(1)),
+            Expr::Lit(Val::Int(2)),
+            Expr::Lit(Val::Int(3)),
+        ])),
+    ];
+    assert_eq!(run(&stmts), Val::Tuple(vec![Val::Int(1), Val::Int(2), Val::Int(3)]));
+}
+
+#[test]
+fn mktup_spanning_multiple_batches_still_gathers_in_order() {
+    let count = 3 * MKTUP_BATCH + 1;
+    let stmts = vec![
+        Stmt::Return(Expr::Mktup((0..count).map(|i| Expr::Lit(Val::Int(i as i64))).collect())),
+    ];
+    let expected = Val::Tuple((0..count).map(|i| Val::Int(i as i64)).collect());
+    assert_eq!(run(&stmts), expected);
+}
+
+// The following only exercise `FunctionCtx::compile` directly (rather than
+// running the result through `Vm`) since they're regression tests for
+// register-allocation bugs in `compile_expr_ctx`'s `Call` arm specifically,
+// not for execution; see `call_executes_a_function_and_returns_its_value`
+// and friends below for actual compile-then-run round trips through a call.
+
+#[test]
+fn call_with_local_callee_and_args_frees_its_temps() {
+    let f = name(0);
+    let stmts = vec![
+        Stmt::Declare(f),
+        Stmt::Assign(f, Expr::Lit(Val::Int(0))),
+        Stmt::RawExpr(Expr::Call(Box::new(Expr::Var(f)), vec![Expr::Lit(Val::Int(1))])),
+    ];
+    // Used to panic in `pop_tmp`'s stack-discipline assert because the
+    // callee/argument temps compiled above `base` were never freed.
+    let mut ctx = FunctionCtx::new();
+    ctx.compile(&stmts);
+}
+
+#[test]
+fn call_with_upvalue_callee_and_no_args_frees_its_temps() {
+    let f = name(0);
+    let g = name(1);
+    let stmts = vec![
+        Stmt::Declare(f),
+        Stmt::Assign(f, Expr::Lit(Val::Int(0))),
+        Stmt::Declare(g),
+        Stmt::Defn(g, vec![], vec![
+            Stmt::RawExpr(Expr::Call(Box::new(Expr::Var(f)), vec![])),
+        ]),
+    ];
+    // `f` resolves as an upvalue inside `g`'s body; its `GetUpval` is
+    // compiled straight into `base` (via `forced_reg`), and `base` itself
+    // has to be freed once the discarded call's result is dropped.
+    let mut ctx = FunctionCtx::new();
+    ctx.compile(&stmts);
+}
+
+#[test]
+fn call_compiles_a_non_foldable_argument_straight_into_its_slot() {
+    let f = name(0);
+    let stmts = vec![
+        Stmt::Declare(f),
+        Stmt::Assign(f, Expr::Lit(Val::Int(0))),
+        Stmt::RawExpr(Expr::Call(
+            Box::new(Expr::Var(f)),
+            vec![Expr::Binop(Binop::Add, Box::new(Expr::Var(f)), Box::new(Expr::Lit(Val::Int(2))))],
+        )),
+    ];
+    let mut ctx = FunctionCtx::new();
+    let code = ctx.compile(&stmts);
+    // The `Binop` argument used to compile into its own throwaway temp
+    // (since `savereg` wouldn't target `arg_reg` directly) and then get
+    // `Copy`'d into place; `forced_reg` lets it land directly in `arg_reg`,
+    // so no `Copy` should appear between the `Add` and the `Call`.
+    let copies_after_add = code.iter().skip_while(|i| !matches!(i, Instr::Add(..)))
+        .skip(1)
+        .take_while(|i| !matches!(i, Instr::Call(..)))
+        .filter(|i| matches!(i, Instr::Copy(..)))
+        .count();
+    assert_eq!(copies_after_add, 0);
+}
+
+#[test]
+fn compile_runs_the_fold_pass() {
+    let stmts = vec![
+        Stmt::Return(Expr::Binop(Binop::Add, Box::new(Expr::Lit(Val::Int(2))), Box::new(Expr::Lit(Val::Int(3))))),
+    ];
+    let mut ctx = FunctionCtx::new();
+    let code = ctx.compile(&stmts);
+    // `fold_stmt` collapses the constant addition to a `Lit` before
+    // `compile_stmt` ever sees it, so no `Add` should be emitted.
+    assert!(!code.iter().any(|instr| matches!(instr, Instr::Add(..))));
+    assert_eq!(Vm::new(ctx.max_reg(), ctx.consts(), ctx.protos()).run(&code).unwrap(), Val::Int(5));
+}
+
+#[test]
+fn defn_binds_closure_into_a_declared_local() {
+    let f = name(0);
+    let stmts = vec![
+        Stmt::Declare(f),
+        Stmt::Defn(f, vec![], vec![Stmt::Return(Expr::Lit(Val::Int(42)))]),
+        Stmt::Return(Expr::Var(f)),
+    ];
+    let mut ctx = FunctionCtx::new();
+    let code = ctx.compile(&stmts);
+    // `f`'s `Declare` claims register 0, so the closure must be built
+    // straight into it rather than some unreachable throwaway temp.
+    assert_eq!(code, vec![Instr::Closure(0, 0), Instr::Return(0)]);
+}
+
+#[test]
+fn defn_proto_carries_its_own_consts() {
+    let f = name(0);
+    let stmts = vec![
+        Stmt::Declare(f),
+        Stmt::Defn(f, vec![], vec![Stmt::Return(Expr::Lit(Val::Int(42)))]),
+    ];
+    let mut ctx = FunctionCtx::new();
+    ctx.compile(&stmts);
+    // The body's `Const(0, 0)` indexes into the child context's own
+    // constant pool, which has to travel with the `Proto` or the literal
+    // it points at is unreachable once the child context is dropped.
+    assert_eq!(ctx.protos[0].consts, vec![Val::Int(42)]);
+}
+
+#[test]
+fn call_executes_a_function_and_returns_its_value() {
+    let f = name(0);
+    let x = name(1);
+    let stmts = vec![
+        Stmt::Declare(f),
+        Stmt::Defn(f, vec![x], vec![
+            Stmt::Return(Expr::Binop(Binop::Add, Box::new(Expr::Var(x)), Box::new(Expr::Lit(Val::Int(1))))),
+        ]),
+        Stmt::Return(Expr::Call(Box::new(Expr::Var(f)), vec![Expr::Lit(Val::Int(41))])),
+    ];
+    assert_eq!(run(&stmts), Val::Int(42));
+}
+
+#[test]
+fn closure_reads_and_writes_a_captured_upvalue() {
+    let counter = name(0);
+    let step = name(1);
+    let stmts = vec![
+        Stmt::Declare(counter),
+        Stmt::Assign(counter, Expr::Lit(Val::Int(0))),
+        Stmt::Declare(step),
+        Stmt::Defn(step, vec![], vec![
+            Stmt::Assign(counter, Expr::Binop(Binop::Add, Box::new(Expr::Var(counter)), Box::new(Expr::Lit(Val::Int(1))))),
+            Stmt::Return(Expr::Var(counter)),
+        ]),
+        Stmt::RawExpr(Expr::Call(Box::new(Expr::Var(step)), vec![])),
+        Stmt::Return(Expr::Call(Box::new(Expr::Var(step)), vec![])),
+    ];
+    // Each call to `step` reads, increments, and writes back the same
+    // captured `counter` cell, so the second call observes the first
+    // call's write rather than a stale snapshot.
+    assert_eq!(run(&stmts), Val::Int(2));
+}
+
+#[test]
+fn recursive_defn_calls_itself_by_name_without_observing_a_stale_capture() {
+    let fact = name(0);
+    let n = name(1);
+    let stmts = vec![
+        Stmt::Declare(fact),
+        Stmt::Defn(fact, vec![n], vec![
+            Stmt::If(
+                Expr::Binop(Binop::Leq, Box::new(Expr::Var(n)), Box::new(Expr::Lit(Val::Int(1)))),
+                vec![Stmt::Return(Expr::Lit(Val::Int(1)))],
+                vec![Stmt::Return(Expr::Binop(
+                    Binop::Mul,
+                    Box::new(Expr::Var(n)),
+                    Box::new(Expr::Call(
+                        Box::new(Expr::Var(fact)),
+                        vec![Expr::Binop(Binop::Sub, Box::new(Expr::Var(n)), Box::new(Expr::Lit(Val::Int(1))))],
+                    )),
+                ))],
+            ),
+        ]),
+        Stmt::Return(Expr::Call(Box::new(Expr::Var(fact)), vec![Expr::Lit(Val::Int(5))])),
+    ];
+    // `fact`'s own body captures `fact` as an upvalue pointing at the same
+    // cell its enclosing `Closure` instruction is about to write into; if
+    // that capture observed a value snapshot instead of the live cell, the
+    // recursive call would see the zero-initialized placeholder instead of
+    // the closure itself.
+    assert_eq!(run(&stmts), Val::Int(120));
+}
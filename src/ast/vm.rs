@@ -0,0 +1,274 @@
+//! A register-based interpreter for the `Instr` stream `FunctionCtx`
+//! compiles, used to validate the jump-offset arithmetic and general
+//! codegen against actual execution instead of just reading the bytecode.
+//!
+//! `Closure`/`GetUpval`/`SetUpval`/`Call` execute via a minimal call stack:
+//! each `Call` spins up a fresh `Vm` over the callee `Proto`'s own
+//! code/consts/protos, and each `Closure` captures its upvalues as shared
+//! `Rc<RefCell<_>>` cells rather than snapshotting their values (see
+//! `RtVal`/`Cell` below). Sharing the cell instead of copying its value at
+//! capture time is what makes a self-recursive `Defn` see its own closure:
+//! the `Closure` instruction writes the finished closure into the same cell
+//! its own body captured as an upvalue, and that write always happens
+//! before the closure can possibly be called.
+
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use bytecode::{Addr, Instr, Val};
+
+use super::{Proto, UpvalSource};
+
+/// Errors raised while executing a compiled instruction stream, surfaced
+/// here instead of panicking so a caller can report them without aborting.
+#[derive(Debug, PartialEq)]
+pub enum RuntimeError {
+    TypeMismatch,
+    DivByZero,
+    RegOutOfRange(Addr),
+    ConstOutOfRange(Addr),
+    ProtoOutOfRange(Addr),
+}
+
+/// A register's runtime content: either a plain `Val` or a closure built by
+/// a `Closure` instruction. Kept separate from `bytecode::Val` since a
+/// closure has to reference this crate's `Proto` tree, which `bytecode`
+/// (lower in the dependency graph than `ast`) has no way to know about.
+#[derive(Debug, Clone)]
+enum RtVal<'a> {
+    Val(Val),
+    Closure(Rc<ClosureObj<'a>>),
+}
+
+/// A closure's runtime representation: the `Proto` it was built from, plus
+/// the cells it captured, shared with whichever register or parent upvalue
+/// they were captured from (see the module doc comment for why that
+/// sharing — not a value snapshot — matters).
+#[derive(Debug)]
+struct ClosureObj<'a> {
+    proto: &'a Proto,
+    upvalues: Vec<Cell<'a>>,
+}
+
+type Cell<'a> = Rc<RefCell<RtVal<'a>>>;
+
+fn new_cell<'a>(val: Val) -> Cell<'a> {
+    Rc::new(RefCell::new(RtVal::Val(val)))
+}
+
+pub struct Vm<'a> {
+    regs: Vec<Cell<'a>>,
+    consts: &'a [Val],
+    protos: &'a [Proto],
+    upvalues: Vec<Cell<'a>>,
+    pc: usize,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(max_reg: Addr, consts: &'a [Val], protos: &'a [Proto]) -> Vm<'a> {
+        Vm {
+            regs: (0..max_reg).map(|_| new_cell(Val::Int(0))).collect(),
+            consts,
+            protos,
+            upvalues: Vec::new(),
+            pc: 0,
+        }
+    }
+
+    fn cell(&self, addr: Addr) -> Result<&Cell<'a>, RuntimeError> {
+        self.regs.get(addr as usize).ok_or(RuntimeError::RegOutOfRange(addr))
+    }
+
+    fn read_cell(&self, addr: Addr) -> Result<RtVal<'a>, RuntimeError> {
+        Ok(self.cell(addr)?.borrow().clone())
+    }
+
+    fn write_cell(&mut self, addr: Addr, val: RtVal<'a>) -> Result<(), RuntimeError> {
+        *self.cell(addr)?.borrow_mut() = val;
+        Ok(())
+    }
+
+    fn reg(&self, addr: Addr) -> Result<Val, RuntimeError> {
+        match self.read_cell(addr)? {
+            RtVal::Val(v) => Ok(v),
+            RtVal::Closure(_) => Err(RuntimeError::TypeMismatch),
+        }
+    }
+
+    fn set_reg(&mut self, addr: Addr, val: Val) -> Result<(), RuntimeError> {
+        self.write_cell(addr, RtVal::Val(val))
+    }
+
+    fn closure(&self, addr: Addr) -> Result<Rc<ClosureObj<'a>>, RuntimeError> {
+        match self.read_cell(addr)? {
+            RtVal::Closure(c) => Ok(c),
+            RtVal::Val(_) => Err(RuntimeError::TypeMismatch),
+        }
+    }
+
+    /// Moves a register's value out, leaving `0` behind, so its owned `Val`
+    /// (e.g. a `Tuple`'s `Vec`) can be extended in place instead of cloned.
+    fn take_reg(&mut self, addr: Addr) -> Result<Val, RuntimeError> {
+        let cell = self.cell(addr)?.clone();
+        let mut slot = cell.borrow_mut();
+        match &mut *slot {
+            RtVal::Val(v) => Ok(mem::replace(v, Val::Int(0))),
+            RtVal::Closure(_) => Err(RuntimeError::TypeMismatch),
+        }
+    }
+
+    fn int(&self, addr: Addr) -> Result<i64, RuntimeError> {
+        match self.reg(addr)? {
+            Val::Int(i) => Ok(i),
+            _ => Err(RuntimeError::TypeMismatch),
+        }
+    }
+
+    fn boolean(&self, addr: Addr) -> Result<bool, RuntimeError> {
+        match self.reg(addr)? {
+            Val::Bool(b) => Ok(b),
+            _ => Err(RuntimeError::TypeMismatch),
+        }
+    }
+
+    fn arith(&mut self, dest: Addr, a: Addr, b: Addr, f: impl FnOnce(i64, i64) -> Result<i64, RuntimeError>) -> Result<(), RuntimeError> {
+        let val = f(self.int(a)?, self.int(b)?)?;
+        self.set_reg(dest, Val::Int(val))
+    }
+
+    fn logic(&mut self, dest: Addr, a: Addr, b: Addr, f: fn(bool, bool) -> bool) -> Result<(), RuntimeError> {
+        let val = f(self.boolean(a)?, self.boolean(b)?);
+        self.set_reg(dest, Val::Bool(val))
+    }
+
+    fn compare(&mut self, dest: Addr, a: Addr, b: Addr, f: fn(i64, i64) -> bool) -> Result<(), RuntimeError> {
+        let val = f(self.int(a)?, self.int(b)?);
+        self.set_reg(dest, Val::Bool(val))
+    }
+
+    /// Clones registers `start..=end` (inclusive) into a fresh `Vec`, shared
+    /// by `MkTup` and `AppendTup`.
+    fn clone_regs(&self, start: Addr, end: Addr) -> Result<Vec<Val>, RuntimeError> {
+        let mut items = Vec::with_capacity((end - start + 1) as usize);
+        for addr in start..=end {
+            items.push(self.reg(addr)?);
+        }
+        Ok(items)
+    }
+
+    /// Resolves a `Proto`'s declared `upvalues` against this frame, either
+    /// by sharing one of this frame's own register cells (`ParentLocal`) or
+    /// by chaining through one of this frame's own already-captured
+    /// upvalues (`ParentUpval`).
+    fn capture_upvalues(&self, sources: &[UpvalSource]) -> Result<Vec<Cell<'a>>, RuntimeError> {
+        sources.iter().map(|src| match *src {
+            UpvalSource::ParentLocal(reg) => Ok(self.cell(reg)?.clone()),
+            UpvalSource::ParentUpval(idx) => {
+                self.upvalues.get(idx as usize).cloned().ok_or(RuntimeError::RegOutOfRange(idx))
+            }
+        }).collect()
+    }
+
+    /// Runs `code` from its first instruction and returns whatever value
+    /// the first `Return` it hits produces (or an empty tuple if the code
+    /// falls off the end without one).
+    pub fn run(&mut self, code: &[Instr]) -> Result<Val, RuntimeError> {
+        self.pc = 0;
+        loop {
+            let instr = match code.get(self.pc) {
+                Some(instr) => *instr,
+                None => return Ok(Val::Tuple(vec![])),
+            };
+
+            match instr {
+                Instr::Const(dest, k) => {
+                    let val = self.consts.get(k as usize).cloned().ok_or(RuntimeError::ConstOutOfRange(k))?;
+                    self.set_reg(dest, val)?;
+                }
+                Instr::Copy(dest, src) => {
+                    let val = self.read_cell(src)?;
+                    self.write_cell(dest, val)?;
+                }
+                Instr::Add(dest, a, b) => self.arith(dest, a, b, |x, y| Ok(x.wrapping_add(y)))?,
+                Instr::Sub(dest, a, b) => self.arith(dest, a, b, |x, y| Ok(x.wrapping_sub(y)))?,
+                Instr::Mul(dest, a, b) => self.arith(dest, a, b, |x, y| Ok(x.wrapping_mul(y)))?,
+                Instr::Div(dest, a, b) => self.arith(dest, a, b, |x, y| if y == 0 { Err(RuntimeError::DivByZero) } else { Ok(x.wrapping_div(y)) })?,
+                Instr::Rem(dest, a, b) => self.arith(dest, a, b, |x, y| if y == 0 { Err(RuntimeError::DivByZero) } else { Ok(x.wrapping_rem(y)) })?,
+                Instr::And(dest, a, b) => self.logic(dest, a, b, |x, y| x && y)?,
+                Instr::Orr(dest, a, b) => self.logic(dest, a, b, |x, y| x || y)?,
+                Instr::Xor(dest, a, b) => self.logic(dest, a, b, |x, y| x ^ y)?,
+                Instr::Gt(dest, a, b) => self.compare(dest, a, b, |x, y| x > y)?,
+                Instr::Lt(dest, a, b) => self.compare(dest, a, b, |x, y| x < y)?,
+                Instr::Geq(dest, a, b) => self.compare(dest, a, b, |x, y| x >= y)?,
+                Instr::Leq(dest, a, b) => self.compare(dest, a, b, |x, y| x <= y)?,
+                Instr::Eq(dest, a, b) => {
+                    let val = Val::Bool(self.reg(a)? == self.reg(b)?);
+                    self.set_reg(dest, val)?;
+                }
+                Instr::Neq(dest, a, b) => {
+                    let val = Val::Bool(self.reg(a)? != self.reg(b)?);
+                    self.set_reg(dest, val)?;
+                }
+                Instr::MkTup(dest, start, end) => {
+                    let items = self.clone_regs(start, end)?;
+                    self.set_reg(dest, Val::Tuple(items))?;
+                }
+                Instr::AppendTup(dest, start, end) => {
+                    let mut items = match self.take_reg(dest)? {
+                        Val::Tuple(items) => items,
+                        _ => return Err(RuntimeError::TypeMismatch),
+                    };
+                    items.extend(self.clone_regs(start, end)?);
+                    self.set_reg(dest, Val::Tuple(items))?;
+                }
+                Instr::CondJump(cond, t, f) => {
+                    let offset = if self.boolean(cond)? { t } else { f };
+                    self.pc = (self.pc as i64 + offset as i64) as usize;
+                    continue;
+                }
+                Instr::Jump(offset) => {
+                    self.pc = (self.pc as i64 + offset as i64) as usize;
+                    continue;
+                }
+                Instr::Return(reg) => return Ok(self.reg(reg)?),
+                Instr::Closure(dest, proto_idx) => {
+                    let proto = self.protos.get(proto_idx as usize).ok_or(RuntimeError::ProtoOutOfRange(proto_idx))?;
+                    let upvalues = self.capture_upvalues(&proto.upvalues)?;
+                    self.write_cell(dest, RtVal::Closure(Rc::new(ClosureObj { proto, upvalues })))?;
+                }
+                Instr::GetUpval(dest, idx) => {
+                    let cell = self.upvalues.get(idx as usize).ok_or(RuntimeError::RegOutOfRange(idx))?.clone();
+                    let val = cell.borrow().clone();
+                    self.write_cell(dest, val)?;
+                }
+                Instr::SetUpval(idx, src) => {
+                    let val = self.read_cell(src)?;
+                    let cell = self.upvalues.get(idx as usize).ok_or(RuntimeError::RegOutOfRange(idx))?.clone();
+                    *cell.borrow_mut() = val;
+                }
+                Instr::Call(base, argc) => {
+                    let closure = self.closure(base)?;
+                    let proto = closure.proto;
+
+                    let call_regs: Vec<Cell<'a>> = (0..proto.max_reg).map(|_| new_cell(Val::Int(0))).collect();
+                    for i in 0..argc {
+                        *call_regs[i as usize].borrow_mut() = self.read_cell(base + 1 + i)?;
+                    }
+
+                    let mut callee = Vm {
+                        regs: call_regs,
+                        consts: &proto.consts,
+                        protos: &proto.protos,
+                        upvalues: closure.upvalues.clone(),
+                        pc: 0,
+                    };
+                    let result = callee.run(&proto.code)?;
+                    self.set_reg(base, result)?;
+                }
+            }
+
+            self.pc += 1;
+        }
+    }
+}